@@ -1,4 +1,8 @@
 use nalgebra::{Matrix4, Point3, Point4, Vector3};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rayon::prelude::*;
+use serde::Deserialize;
 use std::fs::File;
 use std::io::BufWriter;
 use std::path::Path;
@@ -12,6 +16,7 @@ trait Trace {
 #[derive(Debug)]
 struct Hit {
     distance: f32,
+    point: Point3<f32>,
     normal: Vector3<f32>,
 }
 
@@ -21,45 +26,385 @@ struct Ray {
     direction: Vector3<f32>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Deserialize)]
 struct Light {
     position: Point3<f32>,
     intensity: f32,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Deserialize)]
 struct Sphere {
     position: Point3<f32>,
     radius: f32,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Deserialize)]
+struct Triangle {
+    v0: Point3<f32>,
+    v1: Point3<f32>,
+    v2: Point3<f32>,
+    normal: Vector3<f32>,
+}
+
+#[derive(Debug, Deserialize)]
 struct Camera {
     position: Point3<f32>,
     look_at: Point3<f32>,
+    #[serde(default = "default_up")]
+    up: Vector3<f32>,
+    fov: f32,
+    width: u32,
+    height: u32,
+    #[serde(default)]
+    aperture: f32,
+    #[serde(default = "default_focus_distance")]
+    focus_distance: f32,
 }
 
-#[derive(Debug)]
+fn default_up() -> Vector3<f32> {
+    Vector3::new(0.0, 1.0, 0.0)
+}
+
+fn default_focus_distance() -> f32 {
+    1.0
+}
+
+#[derive(Debug, Deserialize)]
 enum Shape {
     Sphere(Sphere),
+    Triangle(Triangle),
 }
 
-#[derive(Debug)]
-struct Material {
-    color: Vector3<f32>,
+#[derive(Debug, Clone, Copy, Deserialize)]
+enum Material {
+    // Matte diffuse surface; `albedo` is its reflectance.
+    Lambertian { albedo: Vector3<f32> },
+    // Reflective surface; `fuzz` perturbs the mirror direction for a brushed look.
+    Metal { albedo: Vector3<f32>, fuzz: f32 },
+    // Transparent surface refracting by its index of refraction.
+    Dielectric { index: f32 },
 }
 
-#[derive(Debug)]
+impl Material {
+    // Scatter an incoming ray off this surface, returning the attenuation and the
+    // continuation ray, or `None` when the ray is absorbed.
+    fn scatter(&self, ray: &Ray, hit: &Hit, rng: &mut StdRng) -> Option<(Vector3<f32>, Ray)> {
+        match self {
+            Material::Lambertian { albedo } => {
+                let direction = cosine_sample_hemisphere(hit.normal, rng);
+                Some((*albedo, scattered_ray(hit, direction)))
+            }
+            Material::Metal { albedo, fuzz } => {
+                let reflected =
+                    reflect(ray.direction.normalize(), hit.normal) + *fuzz * random_in_unit_sphere(rng);
+                // Drop rays scattered below the surface.
+                if reflected.dot(&hit.normal) <= 0.0 {
+                    return None;
+                }
+                Some((*albedo, scattered_ray(hit, reflected.normalize())))
+            }
+            Material::Dielectric { index } => {
+                let direction = ray.direction.normalize();
+                let attenuation = Vector3::new(1.0, 1.0, 1.0);
+
+                // Flip the index ratio and the effective normal when the ray exits.
+                let (outward_normal, ni_over_nt, cosine) = if direction.dot(&hit.normal) > 0.0 {
+                    (-hit.normal, *index, index * direction.dot(&hit.normal))
+                } else {
+                    (hit.normal, 1.0 / index, -direction.dot(&hit.normal))
+                };
+
+                let bounced = match refract(direction, outward_normal, ni_over_nt) {
+                    // Total internal reflection: only reflection is possible.
+                    None => reflect(direction, hit.normal),
+                    Some(refracted) => {
+                        if rng.gen::<f32>() < schlick(cosine, *index) {
+                            reflect(direction, hit.normal)
+                        } else {
+                            refracted
+                        }
+                    }
+                };
+
+                Some((attenuation, scattered_ray(hit, bounced)))
+            }
+        }
+    }
+
+    // The diffuse reflectance used for direct lighting, if the material has one.
+    fn diffuse_albedo(&self) -> Option<Vector3<f32>> {
+        match self {
+            Material::Lambertian { albedo } => Some(*albedo),
+            Material::Metal { .. } | Material::Dielectric { .. } => None,
+        }
+    }
+}
+
+// Offset a scattered ray off the surface along its own direction to avoid self-intersection.
+fn scattered_ray(hit: &Hit, direction: Vector3<f32>) -> Ray {
+    Ray::new(hit.point + direction * SHADOW_BIAS, direction)
+}
+
+// Reflect `d` about `n`: d - 2(d·n)n.
+fn reflect(d: Vector3<f32>, n: Vector3<f32>) -> Vector3<f32> {
+    d - 2.0 * d.dot(&n) * n
+}
+
+// Refract `d` through a surface with normal `n` using Snell's law, or `None` on
+// total internal reflection.
+fn refract(d: Vector3<f32>, n: Vector3<f32>, ni_over_nt: f32) -> Option<Vector3<f32>> {
+    let d = d.normalize();
+    let dt = d.dot(&n);
+    let discriminant = 1.0 - ni_over_nt * ni_over_nt * (1.0 - dt * dt);
+    if discriminant > 0.0 {
+        Some(ni_over_nt * (d - n * dt) - n * discriminant.sqrt())
+    } else {
+        None
+    }
+}
+
+// Schlick's approximation for the Fresnel reflectance.
+fn schlick(cosine: f32, index: f32) -> f32 {
+    let r0 = ((1.0 - index) / (1.0 + index)).powi(2);
+    r0 + (1.0 - r0) * (1.0 - cosine).powi(5)
+}
+
+// A uniformly distributed point inside the unit disk in the xy-plane, by rejection sampling.
+fn random_in_unit_disk(rng: &mut StdRng) -> Vector3<f32> {
+    loop {
+        let p = Vector3::new(rng.gen::<f32>() * 2.0 - 1.0, rng.gen::<f32>() * 2.0 - 1.0, 0.0);
+        if p.norm_squared() < 1.0 {
+            return p;
+        }
+    }
+}
+
+// A uniformly distributed point inside the unit sphere, by rejection sampling.
+fn random_in_unit_sphere(rng: &mut StdRng) -> Vector3<f32> {
+    loop {
+        let p = Vector3::new(
+            rng.gen::<f32>() * 2.0 - 1.0,
+            rng.gen::<f32>() * 2.0 - 1.0,
+            rng.gen::<f32>() * 2.0 - 1.0,
+        );
+        if p.norm_squared() < 1.0 {
+            return p;
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
 struct Object {
     shape: Shape,
     material: Material,
 }
 
+// An axis-aligned bounding box.
+#[derive(Debug, Clone, Copy)]
+struct Aabb {
+    min: Point3<f32>,
+    max: Point3<f32>,
+}
+
+impl Aabb {
+    fn new(min: Point3<f32>, max: Point3<f32>) -> Aabb {
+        Aabb { min, max }
+    }
+
+    // The smallest box enclosing both inputs.
+    fn union(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: Point3::new(
+                self.min.x.min(other.min.x),
+                self.min.y.min(other.min.y),
+                self.min.z.min(other.min.z),
+            ),
+            max: Point3::new(
+                self.max.x.max(other.max.x),
+                self.max.y.max(other.max.y),
+                self.max.z.max(other.max.z),
+            ),
+        }
+    }
+
+    fn centroid(&self) -> Point3<f32> {
+        nalgebra::center(&self.min, &self.max)
+    }
+
+    // Slab test: reject if the per-axis `t` interval is empty or entirely behind the ray.
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> bool {
+        let mut t_min = t_min;
+        let mut t_max = t_max;
+        for axis in 0..3 {
+            let inv = 1.0 / ray.direction[axis];
+            let mut t0 = (self.min[axis] - ray.origin[axis]) * inv;
+            let mut t1 = (self.max[axis] - ray.origin[axis]) * inv;
+            if inv < 0.0 {
+                std::mem::swap(&mut t0, &mut t1);
+            }
+            t_min = t_min.max(t0);
+            t_max = t_max.min(t1);
+            if t_max < t_min {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+// A node in the flat bounding-volume hierarchy.
 #[derive(Debug)]
+enum BvhNode {
+    Leaf { bbox: Aabb, object: usize },
+    Internal { bbox: Aabb, left: usize, right: usize },
+}
+
+impl BvhNode {
+    fn bbox(&self) -> Aabb {
+        match self {
+            BvhNode::Leaf { bbox, .. } | BvhNode::Internal { bbox, .. } => *bbox,
+        }
+    }
+}
+
+// A bounding-volume hierarchy over a scene's objects, stored as a flat array of
+// nodes with the root at the end (the last node pushed during construction).
+#[derive(Debug, Default)]
+struct Bvh {
+    nodes: Vec<BvhNode>,
+}
+
+impl Bvh {
+    fn build(objects: &[Object]) -> Bvh {
+        let mut nodes = Vec::new();
+        if !objects.is_empty() {
+            let mut indices: Vec<usize> = (0..objects.len()).collect();
+            build_node(objects, &mut indices, &mut nodes);
+        }
+        Bvh { nodes }
+    }
+
+    fn intersect(&self, objects: &[Object], ray: &Ray) -> Option<(Hit, usize)> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        self.hit_node(self.nodes.len() - 1, objects, ray, f32::INFINITY)
+    }
+
+    fn hit_node(
+        &self,
+        node: usize,
+        objects: &[Object],
+        ray: &Ray,
+        t_max: f32,
+    ) -> Option<(Hit, usize)> {
+        match &self.nodes[node] {
+            BvhNode::Leaf { bbox, object } => {
+                if !bbox.hit(ray, 0.0, t_max) {
+                    return None;
+                }
+                objects[*object]
+                    .intersect(ray)
+                    .filter(|hit| hit.distance <= t_max)
+                    .map(|hit| (hit, *object))
+            }
+            BvhNode::Internal { bbox, left, right } => {
+                if !bbox.hit(ray, 0.0, t_max) {
+                    return None;
+                }
+                let left_hit = self.hit_node(*left, objects, ray, t_max);
+                // Shrink the search interval to the left child's hit, if any.
+                let t = left_hit.as_ref().map_or(t_max, |(hit, _)| hit.distance);
+                let right_hit = self.hit_node(*right, objects, ray, t);
+                match (left_hit, right_hit) {
+                    (left, None) => left,
+                    (None, right) => right,
+                    (Some(a), Some(b)) => {
+                        if a.0.distance <= b.0.distance {
+                            Some(a)
+                        } else {
+                            Some(b)
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Recursively split `indices` and push the resulting nodes, returning the index of
+// the node just created. At each split we pick the axis with the largest centroid
+// extent, sort by centroid along it, and partition at the median.
+fn build_node(objects: &[Object], indices: &mut [usize], nodes: &mut Vec<BvhNode>) -> usize {
+    if indices.len() == 1 {
+        let object = indices[0];
+        nodes.push(BvhNode::Leaf {
+            bbox: objects[object].bounding_box(),
+            object,
+        });
+        return nodes.len() - 1;
+    }
+
+    let centroid = |&i: &usize| objects[i].bounding_box().centroid();
+    let mut bounds = Aabb::new(centroid(&indices[0]), centroid(&indices[0]));
+    for index in indices.iter() {
+        let c = centroid(index);
+        bounds = bounds.union(&Aabb::new(c, c));
+    }
+    let extent = bounds.max - bounds.min;
+    let axis = if extent.x >= extent.y && extent.x >= extent.z {
+        0
+    } else if extent.y >= extent.z {
+        1
+    } else {
+        2
+    };
+
+    indices.sort_by(|&a, &b| {
+        centroid(&a)[axis]
+            .partial_cmp(&centroid(&b)[axis])
+            .unwrap()
+    });
+
+    let mid = indices.len() / 2;
+    let (left_indices, right_indices) = indices.split_at_mut(mid);
+    let left = build_node(objects, left_indices, nodes);
+    let right = build_node(objects, right_indices, nodes);
+    let bbox = nodes[left].bbox().union(&nodes[right].bbox());
+    nodes.push(BvhNode::Internal { bbox, left, right });
+    nodes.len() - 1
+}
+
+// A triangle mesh loaded from an external Wavefront `.obj` file with a single material.
+#[derive(Debug, Deserialize)]
+struct MeshSpec {
+    path: String,
+    material: Material,
+}
+
+#[derive(Debug, Deserialize)]
 struct Scene {
     objects: Vec<Object>,
     camera: Camera,
     lights: Vec<Light>,
+    #[serde(default)]
+    meshes: Vec<MeshSpec>,
+    #[serde(default)]
+    background: Vector3<f32>,
+    #[serde(default = "default_max_depth")]
+    max_depth: u32,
+    #[serde(default = "default_samples")]
+    samples: u32,
+    #[serde(skip)]
+    bvh: Bvh,
+}
+
+fn default_max_depth() -> u32 {
+    5
+}
+
+fn default_samples() -> u32 {
+    64
 }
 
 impl Ray {
@@ -79,18 +424,34 @@ impl Light {
 }
 
 impl Object {
-    fn sphere(position: Point3<f32>, radius: f32, color: Vector3<f32>) -> Object {
+    fn sphere(position: Point3<f32>, radius: f32, material: Material) -> Object {
         Object {
             shape: Shape::Sphere(Sphere::new(position, radius)),
-            material: Material { color },
+            material,
         }
     }
 }
 
+impl Object {
+    fn bounding_box(&self) -> Aabb {
+        self.shape.bounding_box()
+    }
+}
+
 impl Trace for Object {
     fn intersect(&self, ray: &Ray) -> Option<Hit> {
         match &self.shape {
             Shape::Sphere(sphere) => sphere.intersect(ray),
+            Shape::Triangle(triangle) => triangle.intersect(ray),
+        }
+    }
+}
+
+impl Shape {
+    fn bounding_box(&self) -> Aabb {
+        match self {
+            Shape::Sphere(sphere) => sphere.bounding_box(),
+            Shape::Triangle(triangle) => triangle.bounding_box(),
         }
     }
 }
@@ -102,6 +463,110 @@ impl Sphere {
             radius,
         }
     }
+
+    fn bounding_box(&self) -> Aabb {
+        let radius = Vector3::new(self.radius, self.radius, self.radius);
+        Aabb::new(self.position - radius, self.position + radius)
+    }
+}
+
+impl Triangle {
+    fn new(v0: Point3<f32>, v1: Point3<f32>, v2: Point3<f32>) -> Triangle {
+        let normal = (v1 - v0).cross(&(v2 - v0)).normalize();
+        Triangle { v0, v1, v2, normal }
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        let min = Point3::new(
+            self.v0.x.min(self.v1.x).min(self.v2.x),
+            self.v0.y.min(self.v1.y).min(self.v2.y),
+            self.v0.z.min(self.v1.z).min(self.v2.z),
+        );
+        let max = Point3::new(
+            self.v0.x.max(self.v1.x).max(self.v2.x),
+            self.v0.y.max(self.v1.y).max(self.v2.y),
+            self.v0.z.max(self.v1.z).max(self.v2.z),
+        );
+        Aabb::new(min, max)
+    }
+}
+
+impl Trace for Triangle {
+    // Möller–Trumbore ray/triangle intersection.
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        let e1 = self.v1 - self.v0;
+        let e2 = self.v2 - self.v0;
+        let p = ray.direction.cross(&e2);
+        let det = e1.dot(&p);
+
+        // Ray is parallel to the triangle plane.
+        if det.abs() < 1e-6 {
+            return None;
+        }
+
+        let inv_det = 1.0 / det;
+        let origin = ray.origin - self.v0;
+
+        let u = origin.dot(&p) * inv_det;
+        if !(0.0..=1.0).contains(&u) {
+            return None;
+        }
+
+        let q = origin.cross(&e1);
+        let v = ray.direction.dot(&q) * inv_det;
+        if v < 0.0 || u + v > 1.0 {
+            return None;
+        }
+
+        let distance = e2.dot(&q) * inv_det;
+        if distance < 0.0 {
+            return None;
+        }
+
+        Some(Hit {
+            distance,
+            point: ray.point_at_distance(distance),
+            normal: self.normal,
+        })
+    }
+}
+
+// Load a Wavefront `.obj` file into a set of triangle objects sharing one material.
+// Faces with more than three vertices are fan-triangulated.
+fn load_obj(path: &Path, material: Material) -> Vec<Object> {
+    let contents = std::fs::read_to_string(path).unwrap();
+    let mut vertices: Vec<Point3<f32>> = Vec::new();
+    let mut objects = Vec::new();
+
+    for line in contents.lines() {
+        let mut tokens = line.split_whitespace();
+        match tokens.next() {
+            Some("v") => {
+                let coords: Vec<f32> = tokens.take(3).map(|t| t.parse().unwrap()).collect();
+                vertices.push(Point3::new(coords[0], coords[1], coords[2]));
+            }
+            Some("f") => {
+                // Face indices are 1-based and may carry texture/normal components (`v/vt/vn`).
+                let indices: Vec<usize> = tokens
+                    .map(|t| t.split('/').next().unwrap().parse::<usize>().unwrap() - 1)
+                    .collect();
+                for i in 1..indices.len() - 1 {
+                    let triangle = Triangle::new(
+                        vertices[indices[0]],
+                        vertices[indices[i]],
+                        vertices[indices[i + 1]],
+                    );
+                    objects.push(Object {
+                        shape: Shape::Triangle(triangle),
+                        material,
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    objects
 }
 
 impl Trace for Sphere {
@@ -130,14 +595,32 @@ impl Trace for Sphere {
 
         Some(Hit {
             distance,
+            point: hit_position,
             normal,
         })
     }
 }
 
 impl Camera {
-    fn new(position: Point3<f32>, look_at: Point3<f32>) -> Camera {
-        Camera { position, look_at }
+    fn new(
+        position: Point3<f32>,
+        look_at: Point3<f32>,
+        fov: f32,
+        width: u32,
+        height: u32,
+        aperture: f32,
+        focus_distance: f32,
+    ) -> Camera {
+        Camera {
+            position,
+            look_at,
+            up: default_up(),
+            fov,
+            width,
+            height,
+            aperture,
+            focus_distance,
+        }
     }
 }
 
@@ -147,17 +630,41 @@ impl Scene {
             objects,
             camera,
             lights,
+            meshes: Vec::new(),
+            background: Vector3::new(0.0, 0.0, 0.0),
+            max_depth: default_max_depth(),
+            samples: default_samples(),
+            bvh: Bvh::default(),
         }
+        .finalize()
     }
 
-    // Get a color for a ray
-    fn trace(&self, ray: &Ray) -> Vector3<f32> {
-        let (hit, object) = match self.intersect(ray) {
-            None => return Vector3::new(0.0, 0.0, 0.0),
-            Some(hit) => hit,
-        };
+    // Load a scene from a JSON description file.
+    fn load(path: &Path) -> Scene {
+        let file = File::open(path).unwrap();
+        let scene: Scene = serde_json::from_reader(file).unwrap();
+        scene.finalize()
+    }
+
+    // Load any referenced meshes and build the acceleration structure over the
+    // (now fixed) object set.
+    fn finalize(mut self) -> Scene {
+        for mesh in std::mem::take(&mut self.meshes) {
+            self.objects
+                .extend(load_obj(Path::new(&mesh.path), mesh.material));
+        }
+        self.bvh = Bvh::build(&self.objects);
+        self
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<(Hit, &Object)> {
+        self.bvh
+            .intersect(&self.objects, ray)
+            .map(|(hit, object)| (hit, &self.objects[object]))
+    }
 
-        let hit_point = ray.point_at_distance(hit.distance) + hit.normal * SHADOW_BIAS;
+    // Accumulate the contribution of every light directly visible from a hit point.
+    fn direct_light(&self, hit: &Hit, albedo: Vector3<f32>, hit_point: Point3<f32>) -> Vector3<f32> {
         let mut color = Vector3::new(0.0, 0.0, 0.0);
 
         for light in &self.lights {
@@ -171,35 +678,142 @@ impl Scene {
 
             let shade = f32::max(0.0, hit.normal.dot(&shadow_ray_direction));
 
-            color += shade * object.material.color * light.intensity;
+            color += shade * albedo * light.intensity;
         }
 
         color
     }
+}
 
-    fn intersect(&self, ray: &Ray) -> Option<(Hit, &Object)> {
-        self.objects
-            .iter()
-            .filter_map(|object| object.intersect(ray).map(|hit| (hit, object)))
-            .min_by(|a, b| a.0.distance.partial_cmp(&b.0.distance).unwrap())
+// A renderer turns a primary ray into a radiance estimate for one sample.
+trait Renderer {
+    fn radiance(&self, scene: &Scene, ray: &Ray, rng: &mut StdRng) -> Vector3<f32>;
+}
+
+// The original single-bounce direct-lighting pass: anything not directly lit is black.
+struct DirectTracer;
+
+impl Renderer for DirectTracer {
+    fn radiance(&self, scene: &Scene, ray: &Ray, _rng: &mut StdRng) -> Vector3<f32> {
+        let (hit, object) = match scene.intersect(ray) {
+            None => return scene.background,
+            Some(hit) => hit,
+        };
+
+        match object.material.diffuse_albedo() {
+            Some(albedo) => {
+                let hit_point = hit.point + hit.normal * SHADOW_BIAS;
+                scene.direct_light(&hit, albedo, hit_point)
+            }
+            None => Vector3::new(0.0, 0.0, 0.0),
+        }
     }
 }
 
-const WIDTH: u32 = 1600;
-const HEIGHT: u32 = 1200;
+// A Monte Carlo path tracer: global illumination by recursively bouncing rays,
+// with cosine-weighted hemisphere sampling and Russian-roulette termination.
+struct PathTracer {
+    max_depth: u32,
+}
 
-fn main() {
+impl PathTracer {
+    fn radiance_at(&self, scene: &Scene, ray: &Ray, rng: &mut StdRng, depth: u32) -> Vector3<f32> {
+        let (hit, object) = match scene.intersect(ray) {
+            None => return scene.background,
+            Some(hit) => hit,
+        };
+
+        // Lambertian surfaces still gather direct lighting from the scene lights.
+        let mut color = match object.material.diffuse_albedo() {
+            Some(albedo) => {
+                let hit_point = hit.point + hit.normal * SHADOW_BIAS;
+                scene.direct_light(&hit, albedo, hit_point)
+            }
+            None => Vector3::new(0.0, 0.0, 0.0),
+        };
+
+        if depth >= self.max_depth {
+            return color;
+        }
+
+        let (attenuation, scattered) = match object.material.scatter(ray, &hit, rng) {
+            Some(scatter) => scatter,
+            None => return color,
+        };
+
+        // Russian roulette after a few bounces: survive with probability equal to the
+        // brightest attenuation component and divide the survivor's weight by it. A zero
+        // attenuation can never survive, so the weight stays finite.
+        let mut weight = 1.0;
+        if depth >= 3 {
+            let survive = attenuation.max();
+            if survive <= 0.0 || rng.gen::<f32>() >= survive {
+                return color;
+            }
+            weight = 1.0 / survive;
+        }
+
+        let incoming = self.radiance_at(scene, &scattered, rng, depth + 1);
+        color += weight * attenuation.component_mul(&incoming);
+
+        color
+    }
+}
+
+impl Renderer for PathTracer {
+    fn radiance(&self, scene: &Scene, ray: &Ray, rng: &mut StdRng) -> Vector3<f32> {
+        self.radiance_at(scene, ray, rng, 0)
+    }
+}
+
+// Draw a cosine-weighted direction in the hemisphere around `normal`.
+fn cosine_sample_hemisphere(normal: Vector3<f32>, rng: &mut StdRng) -> Vector3<f32> {
+    let r1 = rng.gen::<f32>();
+    let r2 = rng.gen::<f32>();
+
+    let phi = 2.0 * std::f32::consts::PI * r1;
+    let cos_theta = (1.0 - r2).sqrt();
+    let sin_theta = r2.sqrt();
+
+    // Build an orthonormal basis around the normal, picking the smallest-magnitude
+    // component as the seed axis to avoid a near-degenerate cross product.
+    let seed = if normal.x.abs() <= normal.y.abs() && normal.x.abs() <= normal.z.abs() {
+        Vector3::new(1.0, 0.0, 0.0)
+    } else if normal.y.abs() <= normal.z.abs() {
+        Vector3::new(0.0, 1.0, 0.0)
+    } else {
+        Vector3::new(0.0, 0.0, 1.0)
+    };
+    let t = seed.cross(&normal).normalize();
+    let b = normal.cross(&t);
+
+    (t * (phi.cos() * sin_theta) + b * (phi.sin() * sin_theta) + normal * cos_theta).normalize()
+}
+
+const TILE_SIZE: u32 = 16;
+
+// The scene rendered when no scene file is given on the command line.
+fn default_scene() -> Scene {
     let objects = vec![
         Object::sphere(
             Point3::new(-10.0, 0.0, 0.0),
             2.0,
-            Vector3::new(1.0, 0.0, 0.0),
+            Material::Dielectric { index: 1.5 },
+        ),
+        Object::sphere(
+            Point3::new(0.0, 0.0, 0.0),
+            5.0,
+            Material::Lambertian {
+                albedo: Vector3::new(0.0, 1.0, 0.0),
+            },
         ),
-        Object::sphere(Point3::new(0.0, 0.0, 0.0), 5.0, Vector3::new(0.0, 1.0, 0.0)),
         Object::sphere(
             Point3::new(20.0, 0.0, 0.0),
             10.0,
-            Vector3::new(0.0, 0.0, 1.0),
+            Material::Metal {
+                albedo: Vector3::new(0.8, 0.8, 0.9),
+                fuzz: 0.05,
+            },
         ),
     ];
 
@@ -208,59 +822,139 @@ fn main() {
         Light::new(Point3::new(0.0, 20.0, -50.0), 0.4),
     ];
 
-    let camera = Camera::new(Point3::new(-30.0, 30.0, -20.0), Point3::new(0.0, 0.0, 0.0));
-
-    let scene = Scene::new(objects, camera, lights);
+    let camera = Camera::new(
+        Point3::new(-30.0, 30.0, -20.0),
+        Point3::new(0.0, 0.0, 0.0),
+        std::f32::consts::PI / 4.0,
+        1600,
+        1200,
+        0.5,
+        45.0,
+    );
 
-    let mut scene_buffer = [0; (WIDTH * HEIGHT * 3) as usize];
+    Scene::new(objects, camera, lights)
+}
 
-    let fov = std::f32::consts::PI / 4.0;
-    let fov_adjust = f32::tan(fov / 2.0);
+fn main() {
+    // Load the scene from a JSON file if one was given, otherwise use the default.
+    let scene = match std::env::args().nth(1) {
+        Some(path) => Scene::load(Path::new(&path)),
+        None => default_scene(),
+    };
 
-    let aspect_ratio = WIDTH as f32 / HEIGHT as f32;
+    let width = scene.camera.width;
+    let height = scene.camera.height;
+    let fov_adjust = f32::tan(scene.camera.fov / 2.0);
+    let aspect_ratio = width as f32 / height as f32;
 
     let camera_matrix = Matrix4::face_towards(
         &scene.camera.position,
         &scene.camera.look_at,
-        &Vector3::new(0.0, 1.0, 0.0),
+        &scene.camera.up,
     );
 
-    let start = std::time::Instant::now();
-
-    for y in 0..HEIGHT {
-        for x in 0..WIDTH {
-            let norm_x = (x as f32 + 0.5) / WIDTH as f32;
-            let norm_y = (y as f32 + 0.5) / HEIGHT as f32;
-            // Scale the x pixel according to the aspect ratio
-            let screen_x = (2.0 * norm_x - 1.0) * aspect_ratio * fov_adjust;
-            // Invert the y so +1 is at the top and -1 is at the bottom
-            let screen_y = (1.0 - 2.0 * norm_y) * fov_adjust;
+    // The camera's right/up basis vectors, used to place lens samples.
+    let camera_right = camera_matrix.column(0).xyz();
+    let camera_up = camera_matrix.column(1).xyz();
+    let lens_radius = scene.camera.aperture / 2.0;
+
+    let renderer = PathTracer {
+        max_depth: scene.max_depth,
+    };
+    let samples = scene.samples;
+    let origin = (camera_matrix * Point4::new(0.0, 0.0, 0.0, 1.0)).xyz();
+
+    // Split the image into fixed-size tiles so independent blocks can render in parallel.
+    let tiles: Vec<(u64, u32, u32)> = (0..height)
+        .step_by(TILE_SIZE as usize)
+        .flat_map(|y| (0..width).step_by(TILE_SIZE as usize).map(move |x| (x, y)))
+        .enumerate()
+        .map(|(i, (x, y))| (i as u64, x, y))
+        .collect();
 
-            let camera_point = Point4::new(screen_x, screen_y, 1.0, 1.0);
-
-            let origin = camera_matrix * Point4::new(0.0, 0.0, 0.0, 1.0);
-            let target = camera_matrix * camera_point;
-
-            let direction = (target - origin).xyz().normalize();
+    let start = std::time::Instant::now();
 
-            let ray = Ray::new(origin.xyz(), direction);
-            let index = ((y * WIDTH + x) * 3) as usize;
-            let color = scene.trace(&ray);
+    // Render every tile on the thread pool; the scene is read-only so it is shared by
+    // reference. Each tile seeds its own RNG from its index so results are reproducible
+    // regardless of scheduling.
+    let rendered: Vec<(u32, u32, u32, u32, Vec<u8>)> = tiles
+        .par_iter()
+        .map(|&(seed, tile_x, tile_y)| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let tile_w = (width - tile_x).min(TILE_SIZE);
+            let tile_h = (height - tile_y).min(TILE_SIZE);
+            let mut buffer = vec![0u8; (tile_w * tile_h * 3) as usize];
+
+            for local_y in 0..tile_h {
+                for local_x in 0..tile_w {
+                    let x = tile_x + local_x;
+                    let y = tile_y + local_y;
+
+                    // Fire N rays with jittered sub-pixel offsets and average them for
+                    // anti-aliasing.
+                    let mut color = Vector3::new(0.0, 0.0, 0.0);
+                    for _ in 0..samples {
+                        let norm_x = (x as f32 + rng.gen::<f32>()) / width as f32;
+                        let norm_y = (y as f32 + rng.gen::<f32>()) / height as f32;
+                        // Scale the x pixel according to the aspect ratio
+                        let screen_x = (2.0 * norm_x - 1.0) * aspect_ratio * fov_adjust;
+                        // Invert the y so +1 is at the top and -1 is at the bottom
+                        let screen_y = (1.0 - 2.0 * norm_y) * fov_adjust;
+
+                        let camera_point = Point4::new(screen_x, screen_y, 1.0, 1.0);
+                        let target = (camera_matrix * camera_point).xyz();
+                        let direction = (target - origin).normalize();
+
+                        // The point on the focal plane this pixel stays sharp for.
+                        let focus_point = origin + direction * scene.camera.focus_distance;
+
+                        // Sample a point on the lens disk and aim the ray through the
+                        // focal point, so objects off the focal plane blur.
+                        let lens = lens_radius * random_in_unit_disk(&mut rng);
+                        let offset = camera_right * lens.x + camera_up * lens.y;
+                        let ray_origin = origin + offset;
+                        let ray = Ray::new(ray_origin, (focus_point - ray_origin).normalize());
+
+                        color += renderer.radiance(&scene, &ray, &mut rng);
+                    }
+                    color /= samples as f32;
+
+                    // Gamma-correct the linear color so the output isn't too dark.
+                    let index = ((local_y * tile_w + local_x) * 3) as usize;
+                    buffer[index] = (color.x.sqrt() * 255.0) as u8;
+                    buffer[index + 1] = (color.y.sqrt() * 255.0) as u8;
+                    buffer[index + 2] = (color.z.sqrt() * 255.0) as u8;
+                }
+            }
 
-            scene_buffer[index]     = (color.x * 255.0) as u8;
-            scene_buffer[index + 1] = (color.y * 255.0) as u8;
-            scene_buffer[index + 2] = (color.z * 255.0) as u8;
+            (tile_x, tile_y, tile_w, tile_h, buffer)
+        })
+        .collect();
+
+    // Stitch the per-tile buffers back into the final image.
+    let mut scene_buffer = vec![0u8; (width * height * 3) as usize];
+    for (tile_x, tile_y, tile_w, tile_h, buffer) in rendered {
+        for local_y in 0..tile_h {
+            let src = (local_y * tile_w * 3) as usize;
+            let dst = (((tile_y + local_y) * width + tile_x) * 3) as usize;
+            let row = (tile_w * 3) as usize;
+            scene_buffer[dst..dst + row].copy_from_slice(&buffer[src..src + row]);
         }
     }
 
-    println!("Rendered frame in: {:?}", start.elapsed());
+    let elapsed = start.elapsed();
+    let pixels_per_sec = (width * height) as f64 / elapsed.as_secs_f64();
+    println!(
+        "Rendered frame in: {:?} ({:.0} pixels/sec)",
+        elapsed, pixels_per_sec
+    );
 
     // Output picture
     let path = Path::new("output.png");
     let file = File::create(path).unwrap();
     let writer = BufWriter::new(file);
 
-    let mut encoder = png::Encoder::new(writer, WIDTH, HEIGHT);
+    let mut encoder = png::Encoder::new(writer, width, height);
     encoder.set_color(png::ColorType::RGB);
     encoder.set_depth(png::BitDepth::Eight);
     let mut writer = encoder.write_header().unwrap();